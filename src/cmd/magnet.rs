@@ -0,0 +1,133 @@
+use anyhow::Context;
+use clap::Args;
+use tokio::net::TcpStream;
+
+use crate::peer::connection::Connection;
+use crate::peer::metadata;
+use crate::{Torrent, TorrentInfo};
+
+#[derive(Args)]
+pub struct Magnet {
+    link: String,
+}
+
+/// The pieces of a `magnet:?xt=urn:btih:...&tr=...` URI we care about.
+struct MagnetLink {
+    info_hash: [u8; 20],
+    tracker: String,
+}
+
+impl MagnetLink {
+    fn parse(link: &str) -> crate::Result<MagnetLink> {
+        let query = link
+            .strip_prefix("magnet:?")
+            .context("not a magnet link")?;
+
+        let mut info_hash = None;
+        let mut tracker = None;
+
+        for pair in query.split('&') {
+            let (key, value) = pair.split_once('=').context("malformed magnet param")?;
+            match key {
+                "xt" => {
+                    let hex = value
+                        .strip_prefix("urn:btih:")
+                        .context("unsupported xt namespace")?;
+                    let mut buf = [0u8; 20];
+                    hex::decode_to_slice(hex, &mut buf).context("malformed info-hash")?;
+                    info_hash = Some(buf);
+                }
+                "tr" => {
+                    tracker = Some(urlencoding::decode(value)?.into_owned());
+                }
+                _ => {}
+            }
+        }
+
+        Ok(MagnetLink {
+            info_hash: info_hash.context("missing xt param")?,
+            tracker: tracker.context("missing tr param")?,
+        })
+    }
+}
+
+impl Magnet {
+    pub async fn execute(&self) -> crate::Result<()> {
+        let magnet = MagnetLink::parse(&self.link)?;
+
+        let peers = crate::tracker::discover_peers(&magnet.tracker, magnet.info_hash).await?;
+        let peer = peers.first().context("tracker returned no peers")?;
+
+        let mut conn = Connection::new(TcpStream::connect(peer).await?);
+        conn.handshake(magnet.info_hash).await?;
+
+        // The metadata exchange is a handful of small, fixed-size control
+        // messages with no need for `Connection`'s keep-alive or
+        // streaming-payload machinery, so drive it over a `Framed` instead.
+        let mut ext = conn.into_framed();
+
+        let (ut_metadata_id, metadata_size) = metadata::exchange_handshake(&mut ext).await?;
+        let info_bytes =
+            metadata::fetch_info_dict(&mut ext, ut_metadata_id, metadata_size, magnet.info_hash)
+                .await?;
+
+        let info: TorrentInfo = serde_bencode::from_bytes(&info_bytes)?;
+        let torrent = Torrent {
+            announce: magnet.tracker,
+            info,
+        };
+
+        println!("Tracker URL: {}", torrent.announce);
+        println!("Length: {}", torrent.info.length);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HASH_HEX: &str = "d67d2eb3e8e9cdf2a5aed6e25ce5fde6eb0fd7d1";
+
+    #[test]
+    fn parses_xt_and_tr_in_order() {
+        let link = MagnetLink::parse(&format!(
+            "magnet:?xt=urn:btih:{HASH_HEX}&tr=http%3A%2F%2Ftracker.example%2Fannounce"
+        ))
+        .unwrap();
+        assert_eq!(hex::encode(link.info_hash), HASH_HEX);
+        assert_eq!(link.tracker, "http://tracker.example/announce");
+    }
+
+    #[test]
+    fn parses_tr_before_xt() {
+        let link = MagnetLink::parse(&format!(
+            "magnet:?tr=http%3A%2F%2Ftracker.example%2Fannounce&xt=urn:btih:{HASH_HEX}"
+        ))
+        .unwrap();
+        assert_eq!(hex::encode(link.info_hash), HASH_HEX);
+    }
+
+    #[test]
+    fn rejects_a_non_magnet_link() {
+        assert!(MagnetLink::parse("http://example.com").is_err());
+    }
+
+    #[test]
+    fn rejects_a_missing_xt_param() {
+        let link = "magnet:?tr=http%3A%2F%2Ftracker.example%2Fannounce";
+        assert!(MagnetLink::parse(link).is_err());
+    }
+
+    #[test]
+    fn rejects_a_missing_tr_param() {
+        let link = format!("magnet:?xt=urn:btih:{HASH_HEX}");
+        assert!(MagnetLink::parse(&link).is_err());
+    }
+
+    #[test]
+    fn rejects_an_unsupported_xt_namespace() {
+        let link = format!("magnet:?xt=urn:sha1:{HASH_HEX}&tr=http%3A%2F%2Ftracker.example");
+        assert!(MagnetLink::parse(&link).is_err());
+    }
+}
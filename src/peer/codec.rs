@@ -0,0 +1,97 @@
+//! Adapts the frame parsing in [`connection`](crate::peer::connection) to
+//! `tokio_util`'s `Decoder`/`Encoder` traits, so a peer link can be driven as
+//! a `Stream`/`Sink` of [`Frame`]s and composed with combinators like
+//! `forward`, `buffer`, or `select` instead of only through
+//! [`Connection::read_frame`]/[`write_frame`](Connection::write_frame).
+//!
+//! [`FrameIo`] lets callers that don't need [`Connection`]'s keep-alive or
+//! streaming-payload machinery — small, fixed-size control exchanges like
+//! the BEP-10/BEP-9 handshakes in [`metadata`](crate::peer::metadata) — work
+//! the same way whether they're handed a [`Connection`] or a `Framed`.
+//! `Framed`'s decoder ([`FrameCodec`]) buffers a whole frame before
+//! yielding it, the same as [`Connection::read_frame`]; it does not speak
+//! [`Connection::read_frame_header`]'s bounded-chunk streaming protocol, so
+//! routing a frame type through `Framed` brings back `Connection`'s 64 KiB
+//! `FRAME_MAX` ceiling for that frame. `ut_metadata` pieces are capped at
+//! 16 KiB by BEP-9, well under that ceiling, so
+//! [`metadata`](crate::peer::metadata) is a safe caller; anything that
+//! needs to move larger `Extended` or `Piece` payloads should stay on
+//! [`Connection`]'s streaming path instead, the way
+//! [`Connection::download_piece_windowed`] does.
+
+use bytes::BytesMut;
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed};
+
+use crate::peer::connection::{decode_frame, encode_frame, Connection, Frame};
+use crate::peer::transport::Transport;
+
+/// `Decoder<Item = Frame>` + `Encoder<Frame>` over the same wire format
+/// [`Connection`] speaks.
+#[derive(Debug, Default)]
+pub struct FrameCodec;
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = anyhow::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> crate::Result<Option<Frame>> {
+        decode_frame(src)
+    }
+}
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = anyhow::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> crate::Result<()> {
+        encode_frame(&frame, dst);
+        Ok(())
+    }
+}
+
+/// Wraps `io` in a [`Framed`] so it can be used as a `Stream<Item =
+/// Result<Frame>>` and a `Sink<Frame>`.
+pub fn framed<IO: AsyncRead + AsyncWrite>(io: IO) -> Framed<IO, FrameCodec> {
+    Framed::new(io, FrameCodec)
+}
+
+impl<T: Transport + AsyncRead + AsyncWrite> Connection<T> {
+    /// Gives up the connection's underlying stream as a `Framed` so callers
+    /// can drive it with standard stream combinators. [`Connection::read_frame`]
+    /// and [`write_frame`](Connection::write_frame) remain available as thin
+    /// wrappers for callers that just want request/response semantics.
+    pub fn into_framed(self) -> Framed<T, FrameCodec> {
+        framed(self.into_stream())
+    }
+}
+
+/// A link that can read and write [`Frame`]s, whatever's actually driving
+/// it underneath — a [`Connection`] or a `Framed<_, FrameCodec>`. Lets code
+/// that only wants plain request/response semantics, like the small
+/// control exchanges in [`metadata`](crate::peer::metadata), stay agnostic
+/// to which one it was handed.
+pub trait FrameIo {
+    async fn read_frame(&mut self) -> crate::Result<Option<Frame>>;
+    async fn write_frame(&mut self, frame: &Frame) -> crate::Result<()>;
+}
+
+impl<T: Transport> FrameIo for Connection<T> {
+    async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        Connection::read_frame(self).await
+    }
+
+    async fn write_frame(&mut self, frame: &Frame) -> crate::Result<()> {
+        Connection::write_frame(self, frame).await
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> FrameIo for Framed<T, FrameCodec> {
+    async fn read_frame(&mut self) -> crate::Result<Option<Frame>> {
+        self.next().await.transpose()
+    }
+
+    async fn write_frame(&mut self, frame: &Frame) -> crate::Result<()> {
+        self.send(frame.clone()).await
+    }
+}
@@ -1,12 +1,16 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
 use anyhow::{bail, Context};
-use bytes::{Buf, Bytes, BytesMut};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufWriter};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 use tokio::net::TcpStream;
+use tokio::time::Instant;
 
+use crate::peer::transport::Transport;
 use crate::peer::HandshakePacket;
 use crate::PEER_ID;
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Frame {
     Choke,
     Unchoke,
@@ -56,13 +60,64 @@ pub enum Frame {
         begin: u32,
         length: u32,
     },
+
+    /// BEP-10 extension protocol message. `ext_id` is the sub-message id:
+    /// `0` is reserved for the extension handshake, any other value is an
+    /// id negotiated via the handshake's `"m"` dictionary. `payload` is the
+    /// raw bencoded body; the caller is responsible for decoding it.
+    Extended { ext_id: u8, payload: Bytes },
+}
+
+/// Returned by [`Connection::read_frame`] when [`read_timeout`](Connection::with_timeouts)
+/// elapses without a single byte arriving from the peer (not even a
+/// keep-alive), so callers can tell an idle peer apart from a genuine I/O
+/// failure.
+#[derive(Debug)]
+pub struct ReadTimeout;
+
+impl std::fmt::Display for ReadTimeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no frame received from peer within the read timeout")
+    }
+}
+
+impl std::error::Error for ReadTimeout {}
+
+/// Header of a `Piece` or `Extended` frame whose payload hasn't been pulled
+/// off the wire yet. Returned by [`Connection::read_frame_header`] instead
+/// of a full [`Frame`] so a payload larger than `FRAME_MAX` can be streamed
+/// straight to a writer via [`Connection::read_payload_chunk`] instead of
+/// buffered whole. `len` is the number of payload bytes still to read.
+#[derive(Debug, PartialEq)]
+pub enum FrameHeader {
+    Piece { index: u32, begin: u32, len: u32 },
+    Extended { ext_id: u8, len: u32 },
 }
 
-/// A wrapper around the `TcpStream` to send and receive framed messages.
+/// Either a frame of any other kind read in full, or the header of a
+/// `Piece`/`Extended` frame (whatever its size) whose payload the caller must
+/// drain with
+/// [`Connection::read_payload_chunk`] before reading anything else off the
+/// connection.
+#[derive(Debug, PartialEq)]
+pub enum StreamFrame {
+    Whole(Frame),
+    Header(FrameHeader),
+}
+
+/// A wrapper around a [`Transport`] to send and receive framed messages.
+/// Generic over the transport so the framing logic below is reused
+/// unchanged across backends; defaults to plain TCP.
 #[derive(Debug)]
-pub struct Connection {
-    stream: BufWriter<TcpStream>,
+pub struct Connection<T: Transport = TcpStream> {
+    stream: T,
     buf: BytesMut,
+    /// When we last put bytes on the wire; drives the keep-alive heartbeat.
+    last_write: Instant,
+    /// When we last saw any bytes from the peer; drives the idle timeout.
+    last_read: Instant,
+    keep_alive_interval: Duration,
+    read_timeout: Duration,
 }
 
 /// 4B
@@ -71,14 +126,61 @@ const U32_SIZE: usize = std::mem::size_of::<u32>();
 /// 65536B (64KiB)
 const FRAME_MAX: usize = 1 << 16;
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Connection {
+/// Size of a single requested block. All current implementations use this
+/// value and close connections which request more.
+pub const BLOCK_SIZE: u32 = 1 << 14;
+
+/// Default number of `Request` frames kept outstanding at once when
+/// pipelining a piece download.
+const DEFAULT_WINDOW: usize = 5;
+
+/// How long to wait for a block before re-requesting it.
+const BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the outbound side can stay idle before we send a keep-alive,
+/// per the "2 minute" cadence most clients use.
+const DEFAULT_KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(120);
+
+/// How long we'll wait without hearing anything (not even a keep-alive)
+/// before giving up on a peer.
+const DEFAULT_READ_TIMEOUT: Duration = Duration::from_secs(150);
+
+/// Size of the intermediate buffer used to pull bytes off the socket, both
+/// for ordinary frames and while streaming a large payload. Partial reads
+/// smaller than this are simply accumulated across calls.
+const READ_CHUNK_SIZE: usize = 4096;
+
+impl<T: Transport> Connection<T> {
+    pub fn new(stream: T) -> Connection<T> {
+        Self::with_timeouts(stream, DEFAULT_KEEP_ALIVE_INTERVAL, DEFAULT_READ_TIMEOUT)
+    }
+
+    /// Same as [`Connection::new`] but with caller-chosen keep-alive and
+    /// read-idle timeouts, so tests can use durations shorter than the
+    /// minutes-long defaults.
+    pub fn with_timeouts(
+        stream: T,
+        keep_alive_interval: Duration,
+        read_timeout: Duration,
+    ) -> Connection<T> {
+        let now = Instant::now();
         Connection {
-            stream: BufWriter::new(stream),
+            stream,
             buf: BytesMut::with_capacity(32 * 1024),
+            last_write: now,
+            last_read: now,
+            keep_alive_interval,
+            read_timeout,
         }
     }
 
+    /// Gives up the connection, handing back its underlying stream. Meant
+    /// for callers switching to [`codec::framed`](crate::peer::codec::framed)
+    /// right after the handshake, before any frames have been read.
+    pub(crate) fn into_stream(self) -> T {
+        self.stream
+    }
+
     pub async fn handshake(&mut self, info_hash: [u8; 20]) -> crate::Result<HandshakePacket> {
         let mut packet = HandshakePacket::new(info_hash, *PEER_ID);
         self.stream
@@ -98,145 +200,450 @@ impl Connection {
             if let Some(frame) = self.parse_frame()? {
                 return Ok(Some(frame));
             }
-
-            if 0 == self.stream.read_buf(&mut self.buf).await? {
-                if self.buf.is_empty() {
-                    return Ok(None);
-                } else {
-                    bail!("connection reset by peer")
-                }
+            if !self.fill_buf().await? {
+                return Ok(None);
             }
         }
     }
 
-    fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
-        if self.buf.len() < U32_SIZE {
-            // Not enough data to read length marker.
-            return Ok(None);
+    /// Like [`Connection::read_frame`], but for `Piece` and `Extended`
+    /// frames it stops after the small fixed-size header instead of
+    /// requiring the whole payload to fit under `FRAME_MAX`. A
+    /// [`StreamFrame::Header`] must be fully drained with
+    /// [`Connection::read_payload_chunk`] before this (or `read_frame`) is
+    /// called again.
+    pub async fn read_frame_header(&mut self) -> crate::Result<Option<StreamFrame>> {
+        loop {
+            if let Some(frame) = decode_frame_header(&mut self.buf)? {
+                return Ok(Some(frame));
+            }
+            if !self.fill_buf().await? {
+                return Ok(None);
+            }
         }
+    }
 
-        // Read length marker, this should not fail since we know we have 4 bytes in the buffer.
-        let len = u32::from_be_bytes(self.buf[..4].try_into().unwrap()) as usize;
-        if len == 0 {
-            // `KeepAlive` messsage, skip length marker and continue parsing since
-            // we may still have bytes left in the buffer.
-            let _ = self.buf.get_u32(); // self.buf.advance(4);
-            return self.parse_frame();
+    /// Pulls up to `max` bytes of the payload described by a prior
+    /// [`FrameHeader`], decrementing `*remaining` by however much was read.
+    /// Drains whatever's already buffered before reading fresh off the
+    /// wire, so peak memory stays bounded by `max` regardless of how large
+    /// the frame's total payload is. Callers are expected to write each
+    /// chunk straight to its destination (e.g. a file, at the right
+    /// offset) rather than accumulate them.
+    pub async fn read_payload_chunk(&mut self, remaining: &mut u32, max: usize) -> crate::Result<Bytes> {
+        let want = max.min(*remaining as usize);
+        if want == 0 {
+            return Ok(Bytes::new());
         }
 
-        // Check that the length is not too large to avoid a denial of
-        // service attack where the server runs out of memory.
-        if len > FRAME_MAX {
-            bail!("protocol error; frame of length {len} is too large.")
-            /* return Err(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Frame of length {} is too large.", len),
-            )); */
+        while self.buf.len() < want {
+            if !self.fill_buf().await? {
+                bail!("connection reset by peer mid-payload");
+            }
         }
 
-        if self.buf.len() < U32_SIZE + len {
-            // The full data has not yet arrived.
-            //
-            // We reserve more space in the buffer. This is not strictly
-            // necessary, but is a good idea performance-wise.
-            self.buf.reserve(U32_SIZE + len - self.buf.len());
+        *remaining -= want as u32;
+        Ok(self.buf.split_to(want).freeze())
+    }
+
+    fn parse_frame(&mut self) -> crate::Result<Option<Frame>> {
+        decode_frame(&mut self.buf)
+    }
+
+    /// Does one (possibly partial) socket read into `self.buf`, sending a
+    /// keep-alive and looping instead of returning whenever the outbound
+    /// side has been idle for `keep_alive_interval` while the read is still
+    /// pending, and failing with [`ReadTimeout`] if nothing arrives within
+    /// `read_timeout` of the last successful read. Returns `false` only on a
+    /// clean EOF with nothing left buffered to parse.
+    async fn fill_buf(&mut self) -> crate::Result<bool> {
+        let read_deadline = self.last_read + self.read_timeout;
+        let mut scratch = [0u8; READ_CHUNK_SIZE];
 
-            // We need more bytes to form the next frame.
-            return Ok(None);
-        }
+        let n = loop {
+            let keep_alive_deadline = self.last_write + self.keep_alive_interval;
 
-        // Skip length marker, it has already been parsed.
-        self.buf.advance(U32_SIZE);
-
-        let frame = match self.buf.get_u8() {
-            0 => Frame::Choke,
-            1 => Frame::Unchoke,
-            2 => Frame::Interested,
-            3 => Frame::NotInterested,
-            4 => {
-                let index = self.buf.get_u32();
-                Frame::Have(index)
-            }
-            5 => {
-                let bitfield = self.buf.split_to(len - 1).freeze();
-                Frame::Bitfield(bitfield)
+            tokio::select! {
+                biased;
+                read = self.stream.read(&mut scratch) => break read?,
+                _ = tokio::time::sleep_until(keep_alive_deadline) => {
+                    self.send_keep_alive().await?;
+                }
+                _ = tokio::time::sleep_until(read_deadline) => return Err(ReadTimeout.into()),
             }
-            6 => Frame::Request {
-                index: self.buf.get_u32(),
-                begin: self.buf.get_u32(),
-                length: self.buf.get_u32(),
-            },
-            7 => Frame::Piece {
-                index: self.buf.get_u32(),
-                begin: self.buf.get_u32(),
-                chunk: self.buf.split_to(len - 9).freeze(),
-            },
-            8 => Frame::Cancel {
-                index: self.buf.get_u32(),
-                begin: self.buf.get_u32(),
-                length: self.buf.get_u32(),
-            },
-            // TODO: Implemenet custom protocol error.
-            n => bail!("protocol error; invalid message kind {n}"),
         };
+        self.last_read = Instant::now();
 
-        Ok(Some(frame))
+        match n {
+            0 if self.buf.is_empty() => Ok(false),
+            0 => bail!("connection reset by peer"),
+            n => {
+                self.buf.extend_from_slice(&scratch[..n]);
+                Ok(true)
+            }
+        }
     }
 
     pub async fn write_frame(&mut self, frame: &Frame) -> crate::Result<()> {
-        match frame {
-            Frame::Have(index) => {
-                self.stream.write_u32(5).await?;
-                self.stream.write_u8(4).await?;
-                self.stream.write_u32(*index).await?;
+        let mut buf = BytesMut::new();
+        encode_frame(frame, &mut buf);
+        self.stream.write_all(&buf).await?;
+        self.stream.flush().await?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// Sends a zero-length keep-alive message. Bypasses [`Frame`]/`encode_frame`
+    /// since a keep-alive isn't a message kind, just an empty length prefix.
+    async fn send_keep_alive(&mut self) -> crate::Result<()> {
+        self.stream.write_all(&0u32.to_be_bytes()).await?;
+        self.stream.flush().await?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+
+    /// Downloads `piece_len` bytes of piece `index`, keeping up to
+    /// [`DEFAULT_WINDOW`] `Request`s outstanding at once instead of waiting
+    /// for each block's `Piece` reply before sending the next request.
+    pub async fn download_piece(&mut self, index: u32, piece_len: u32) -> crate::Result<Bytes> {
+        self.download_piece_windowed(index, piece_len, DEFAULT_WINDOW)
+            .await
+    }
+
+    /// Same as [`Connection::download_piece`] but with a caller-chosen
+    /// in-flight window instead of [`DEFAULT_WINDOW`]. A block that times
+    /// out or whose `Piece` reply doesn't match what was requested is
+    /// cancelled with a [`Frame::Cancel`] before being re-queued, so the
+    /// peer doesn't send it late on top of the re-request.
+    pub async fn download_piece_windowed(
+        &mut self,
+        index: u32,
+        piece_len: u32,
+        window: usize,
+    ) -> crate::Result<Bytes> {
+        let mut piece = BytesMut::zeroed(piece_len as usize);
+        let mut received = 0u32;
+
+        // Blocks not yet requested, in the order they should be sent.
+        let mut pending: VecDeque<(u32, u32)> = blocks(piece_len).collect();
+        // Blocks we've requested but haven't gotten a `Piece` reply for yet.
+        let mut in_flight: Vec<(u32, u32)> = Vec::with_capacity(window);
+
+        while received < piece_len {
+            while in_flight.len() < window {
+                let Some((begin, length)) = pending.pop_front() else {
+                    break;
+                };
+                self.write_frame(&Frame::Request {
+                    index,
+                    begin,
+                    length,
+                })
+                .await?;
+                in_flight.push((begin, length));
             }
-            Frame::Bitfield(bitfield) => {
-                self.stream.write_u32((1 + bitfield.len()) as u32).await?;
-                self.stream.write_u8(u8::from(frame)).await?;
-                self.stream.write_all(bitfield).await?;
+
+            // Read the header only, then stream the `Piece` payload straight
+            // into `piece` in bounded chunks instead of buffering the whole
+            // block through `read_frame`.
+            let header = match tokio::time::timeout(BLOCK_TIMEOUT, self.read_frame_header()).await {
+                Ok(header) => header?.context("connection closed mid-piece")?,
+                Err(_elapsed) => {
+                    // Nothing arrived in time; cancel everything still
+                    // outstanding so the peer doesn't send it late on top of
+                    // the re-request, then re-request it.
+                    self.cancel_in_flight(index, &mut in_flight, &mut pending).await?;
+                    continue;
+                }
+            };
+
+            match header {
+                StreamFrame::Header(FrameHeader::Piece { begin, mut len, .. }) => {
+                    let slot = in_flight.iter().position(|&(b, _)| b == begin);
+                    let valid = matches!(slot, Some(i) if in_flight[i].1 == len);
+
+                    if valid {
+                        let mut offset = begin;
+                        while len > 0 {
+                            let chunk = self.read_payload_chunk(&mut len, BLOCK_SIZE as usize).await?;
+                            piece[offset as usize..offset as usize + chunk.len()].copy_from_slice(&chunk);
+                            offset += chunk.len() as u32;
+                            received += chunk.len() as u32;
+                        }
+                        in_flight.remove(slot.unwrap());
+                    } else {
+                        // Stale, duplicate, or size-mismatched block: it's
+                        // already framed off the wire so it has to be
+                        // drained, but not trusted or copied into `piece`.
+                        while len > 0 {
+                            self.read_payload_chunk(&mut len, BLOCK_SIZE as usize).await?;
+                        }
+                        if let Some(i) = slot {
+                            let (begin, length) = in_flight.remove(i);
+                            self.write_frame(&Frame::Cancel { index, begin, length }).await?;
+                            pending.push_back((begin, length));
+                        }
+                    }
+                }
+                StreamFrame::Header(FrameHeader::Extended { mut len, .. }) => {
+                    // Not relevant to a piece download, but already framed
+                    // off the wire, so drain it before reading on.
+                    while len > 0 {
+                        self.read_payload_chunk(&mut len, BLOCK_SIZE as usize).await?;
+                    }
+                }
+                StreamFrame::Whole(Frame::Choke) => {
+                    // Peer choked us; everything in flight has to be
+                    // re-requested once we're unchoked again.
+                    pending.extend(in_flight.drain(..));
+                    loop {
+                        match self
+                            .read_frame()
+                            .await?
+                            .context("connection closed while choked")?
+                        {
+                            Frame::Unchoke => break,
+                            _ => continue,
+                        }
+                    }
+                }
+                // Not relevant to a piece download, keep going.
+                StreamFrame::Whole(_) => continue,
             }
-            Frame::Request {
-                index,
-                begin,
-                length,
-            } => {
-                self.stream.write_u32(13).await?;
-                self.stream.write_u8(u8::from(frame)).await?;
-                self.stream.write_u32(*index).await?;
-                self.stream.write_u32(*begin).await?;
-                self.stream.write_u32(*length).await?;
+        }
+
+        Ok(piece.freeze())
+    }
+
+    /// Cancels every block in `in_flight` and moves it back onto `pending`
+    /// for re-request, e.g. after [`BLOCK_TIMEOUT`] elapses with no reply.
+    async fn cancel_in_flight(
+        &mut self,
+        index: u32,
+        in_flight: &mut Vec<(u32, u32)>,
+        pending: &mut VecDeque<(u32, u32)>,
+    ) -> crate::Result<()> {
+        for (begin, length) in in_flight.drain(..) {
+            self.write_frame(&Frame::Cancel { index, begin, length }).await?;
+            pending.push_back((begin, length));
+        }
+        Ok(())
+    }
+}
+
+/// Splits a piece of `piece_len` bytes into `(begin, length)` block
+/// requests, each at most [`BLOCK_SIZE`].
+fn blocks(piece_len: u32) -> impl Iterator<Item = (u32, u32)> {
+    (0..piece_len).step_by(BLOCK_SIZE as usize).map(move |begin| {
+        let length = BLOCK_SIZE.min(piece_len - begin);
+        (begin, length)
+    })
+}
+
+/// Tries to pull one complete [`Frame`] off the front of `buf`, leaving
+/// anything past it untouched. Shared by [`Connection::parse_frame`] and
+/// [`FrameCodec`](crate::peer::codec::FrameCodec)'s `Decoder` impl so the
+/// two stay in lockstep.
+pub(crate) fn decode_frame(buf: &mut BytesMut) -> crate::Result<Option<Frame>> {
+    if buf.len() < U32_SIZE {
+        // Not enough data to read length marker.
+        return Ok(None);
+    }
+
+    // Read length marker, this should not fail since we know we have 4 bytes in the buffer.
+    let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+    if len == 0 {
+        // `KeepAlive` messsage, skip length marker and continue parsing since
+        // we may still have bytes left in the buffer.
+        let _ = buf.get_u32(); // buf.advance(4);
+        return decode_frame(buf);
+    }
+
+    // Check that the length is not too large to avoid a denial of
+    // service attack where the server runs out of memory.
+    if len > FRAME_MAX {
+        bail!("protocol error; frame of length {len} is too large.")
+    }
+
+    if buf.len() < U32_SIZE + len {
+        // The full data has not yet arrived.
+        //
+        // We reserve more space in the buffer. This is not strictly
+        // necessary, but is a good idea performance-wise.
+        buf.reserve(U32_SIZE + len - buf.len());
+
+        // We need more bytes to form the next frame.
+        return Ok(None);
+    }
+
+    // Skip length marker, it has already been parsed.
+    buf.advance(U32_SIZE);
+
+    let frame = match buf.get_u8() {
+        0 => Frame::Choke,
+        1 => Frame::Unchoke,
+        2 => Frame::Interested,
+        3 => Frame::NotInterested,
+        4 => {
+            let index = buf.get_u32();
+            Frame::Have(index)
+        }
+        5 => {
+            let bitfield = buf.split_to(len - 1).freeze();
+            Frame::Bitfield(bitfield)
+        }
+        6 => Frame::Request {
+            index: buf.get_u32(),
+            begin: buf.get_u32(),
+            length: buf.get_u32(),
+        },
+        7 => Frame::Piece {
+            index: buf.get_u32(),
+            begin: buf.get_u32(),
+            chunk: buf.split_to(len - 9).freeze(),
+        },
+        8 => Frame::Cancel {
+            index: buf.get_u32(),
+            begin: buf.get_u32(),
+            length: buf.get_u32(),
+        },
+        20 => {
+            let ext_id = buf.get_u8();
+            let payload = buf.split_to(len - 2).freeze();
+            Frame::Extended { ext_id, payload }
+        }
+        // TODO: Implemenet custom protocol error.
+        n => bail!("protocol error; invalid message kind {n}"),
+    };
+
+    Ok(Some(frame))
+}
+
+/// Like [`decode_frame`], but for `Piece` (id `7`) and `Extended` (id `20`)
+/// frames it stops after their small fixed-size header and leaves the
+/// (possibly huge) payload bytes at the front of `buf`, instead of
+/// requiring `FRAME_MAX` to hold the whole frame. Every other kind is still
+/// parsed in full via `decode_frame`, since those payloads are already
+/// small and bounded. Shared by [`Connection::read_frame_header`].
+pub(crate) fn decode_frame_header(buf: &mut BytesMut) -> crate::Result<Option<StreamFrame>> {
+    if buf.len() < U32_SIZE {
+        return Ok(None);
+    }
+
+    let len = u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+    if len == 0 {
+        let _ = buf.get_u32();
+        return decode_frame_header(buf);
+    }
+
+    if buf.len() < U32_SIZE + 1 {
+        // Not enough data yet to even see the message kind.
+        return Ok(None);
+    }
+
+    match buf[U32_SIZE] {
+        7 => {
+            if len < 9 {
+                bail!("protocol error; piece frame of length {len} is too short");
             }
-            Frame::Piece {
-                index,
-                begin,
-                chunk,
-            } => {
-                self.stream.write_u32((9 + chunk.len()) as u32).await?;
-                self.stream.write_u8(u8::from(frame)).await?;
-                self.stream.write_u32(*index).await?;
-                self.stream.write_u32(*begin).await?;
-                self.stream.write_all(chunk).await?;
+            const HEADER_LEN: usize = U32_SIZE + 1 + 4 + 4;
+            if buf.len() < HEADER_LEN {
+                buf.reserve(HEADER_LEN - buf.len());
+                return Ok(None);
             }
-            Frame::Cancel {
+            buf.advance(U32_SIZE + 1);
+            let index = buf.get_u32();
+            let begin = buf.get_u32();
+            Ok(Some(StreamFrame::Header(FrameHeader::Piece {
                 index,
                 begin,
-                length,
-            } => {
-                self.stream.write_u32(13).await?;
-                self.stream.write_u8(u8::from(frame)).await?;
-                self.stream.write_u32(*index).await?;
-                self.stream.write_u32(*begin).await?;
-                self.stream.write_u32(*length).await?;
+                len: (len - 9) as u32,
+            })))
+        }
+        20 => {
+            if len < 2 {
+                bail!("protocol error; extended frame of length {len} is too short");
             }
-            // `Choke`, `Unchoke`, `Interested`, and 'NotInterested' have no payload.
-            frame => {
-                self.stream.write_u32(1).await?;
-                self.stream.write_u8(u8::from(frame)).await?;
+            const HEADER_LEN: usize = U32_SIZE + 1 + 1;
+            if buf.len() < HEADER_LEN {
+                buf.reserve(HEADER_LEN - buf.len());
+                return Ok(None);
             }
-        };
+            buf.advance(U32_SIZE + 1);
+            let ext_id = buf.get_u8();
+            Ok(Some(StreamFrame::Header(FrameHeader::Extended {
+                ext_id,
+                len: (len - 2) as u32,
+            })))
+        }
+        _ => {
+            if len > FRAME_MAX {
+                bail!("protocol error; frame of length {len} is too large.")
+            }
+            decode_frame(buf).map(|frame| frame.map(StreamFrame::Whole))
+        }
+    }
+}
 
-        self.stream.flush().await?;
-        Ok(())
+/// Serializes `frame` onto the end of `dst` in wire format. Shared by
+/// [`Connection::write_frame`] and [`FrameCodec`](crate::peer::codec::FrameCodec)'s
+/// `Encoder` impl so the two stay in lockstep.
+pub(crate) fn encode_frame(frame: &Frame, dst: &mut BytesMut) {
+    match frame {
+        Frame::Have(index) => {
+            dst.put_u32(5);
+            dst.put_u8(4);
+            dst.put_u32(*index);
+        }
+        Frame::Bitfield(bitfield) => {
+            dst.put_u32((1 + bitfield.len()) as u32);
+            dst.put_u8(u8::from(frame));
+            dst.extend_from_slice(bitfield);
+        }
+        Frame::Request {
+            index,
+            begin,
+            length,
+        } => {
+            dst.put_u32(13);
+            dst.put_u8(u8::from(frame));
+            dst.put_u32(*index);
+            dst.put_u32(*begin);
+            dst.put_u32(*length);
+        }
+        Frame::Piece {
+            index,
+            begin,
+            chunk,
+        } => {
+            dst.put_u32((9 + chunk.len()) as u32);
+            dst.put_u8(u8::from(frame));
+            dst.put_u32(*index);
+            dst.put_u32(*begin);
+            dst.extend_from_slice(chunk);
+        }
+        Frame::Cancel {
+            index,
+            begin,
+            length,
+        } => {
+            dst.put_u32(13);
+            dst.put_u8(u8::from(frame));
+            dst.put_u32(*index);
+            dst.put_u32(*begin);
+            dst.put_u32(*length);
+        }
+        Frame::Extended { ext_id, payload } => {
+            dst.put_u32((2 + payload.len()) as u32);
+            dst.put_u8(u8::from(frame));
+            dst.put_u8(*ext_id);
+            dst.extend_from_slice(payload);
+        }
+        // `Choke`, `Unchoke`, `Interested`, and 'NotInterested' have no payload.
+        frame => {
+            dst.put_u32(1);
+            dst.put_u8(u8::from(frame));
+        }
     }
 }
 
@@ -253,6 +660,264 @@ impl From<&Frame> for u8 {
             Request { .. } => 6,
             Piece { .. } => 7,
             Cancel { .. } => 8,
+            Extended { .. } => 20,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// Spins up an in-memory `Connection` and its peer-side half, so frame
+    /// parsing can be exercised without a real socket.
+    fn test_connection() -> (Connection<tokio::io::DuplexStream>, tokio::io::DuplexStream) {
+        let (ours, theirs) = tokio::io::duplex(1024);
+        (Connection::new(ours), theirs)
+    }
+
+    #[tokio::test]
+    async fn reads_a_frame_split_across_several_writes() {
+        let (mut conn, mut peer) = test_connection();
+
+        // `Have(7)`: length prefix, id, then the index, each written
+        // separately so `read_frame` has to reassemble them itself.
+        peer.write_all(&5u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&[4]).await.unwrap();
+        peer.write_all(&7u32.to_be_bytes()).await.unwrap();
+
+        assert_eq!(conn.read_frame().await.unwrap(), Some(Frame::Have(7)));
+    }
+
+    #[tokio::test]
+    async fn reads_a_length_prefix_split_byte_by_byte() {
+        let (mut conn, mut peer) = test_connection();
+
+        for byte in 1u32.to_be_bytes() {
+            peer.write_all(&[byte]).await.unwrap();
         }
+        peer.write_all(&[0]).await.unwrap(); // `Choke`
+
+        assert_eq!(conn.read_frame().await.unwrap(), Some(Frame::Choke));
+    }
+
+    #[tokio::test]
+    async fn skips_keep_alives_and_returns_the_next_real_frame() {
+        let (mut conn, mut peer) = test_connection();
+
+        peer.write_all(&0u32.to_be_bytes()).await.unwrap(); // keep-alive
+        peer.write_all(&0u32.to_be_bytes()).await.unwrap(); // keep-alive
+        peer.write_all(&1u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&[1]).await.unwrap(); // `Unchoke`
+
+        assert_eq!(conn.read_frame().await.unwrap(), Some(Frame::Unchoke));
+    }
+
+    #[tokio::test]
+    async fn returns_none_on_clean_eof() {
+        let (mut conn, peer) = test_connection();
+        drop(peer);
+
+        assert_eq!(conn.read_frame().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn round_trips_an_extended_frame() {
+        let (mut conn, mut peer_conn) = {
+            let (ours, theirs) = tokio::io::duplex(1024);
+            (Connection::new(ours), Connection::new(theirs))
+        };
+
+        let frame = Frame::Extended {
+            ext_id: 3,
+            payload: Bytes::from_static(b"d1:ai5ee"),
+        };
+        conn.write_frame(&frame).await.unwrap();
+
+        assert_eq!(peer_conn.read_frame().await.unwrap(), Some(frame));
+    }
+
+    #[tokio::test]
+    async fn sends_a_keep_alive_when_the_outbound_side_goes_idle() {
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let mut conn = Connection::with_timeouts(ours, Duration::from_millis(10), Duration::from_secs(5));
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        tokio::spawn(async move {
+            let _ = conn.read_frame().await;
+        });
+
+        let mut marker = [0u8; 4];
+        tokio::time::timeout(Duration::from_millis(200), theirs.read_exact(&mut marker))
+            .await
+            .expect("keep-alive never arrived")
+            .unwrap();
+        assert_eq!(marker, 0u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn sends_a_keep_alive_while_a_read_is_already_pending() {
+        // Unlike the idle-side test above, this starts `read_frame` reading
+        // immediately instead of sleeping past `keep_alive_interval` first,
+        // so the keep-alive has to be emitted mid-read rather than on
+        // `fill_buf`'s very first call.
+        let (ours, mut theirs) = tokio::io::duplex(1024);
+        let mut conn = Connection::with_timeouts(ours, Duration::from_millis(20), Duration::from_secs(5));
+
+        tokio::spawn(async move {
+            let _ = conn.read_frame().await;
+        });
+
+        let mut marker = [0u8; 4];
+        tokio::time::timeout(Duration::from_millis(200), theirs.read_exact(&mut marker))
+            .await
+            .expect("keep-alive never arrived while the read was pending")
+            .unwrap();
+        assert_eq!(marker, 0u32.to_be_bytes());
+    }
+
+    #[tokio::test]
+    async fn read_frame_times_out_when_the_peer_goes_silent() {
+        let (mut conn, _peer) = {
+            let (ours, theirs) = tokio::io::duplex(1024);
+            (
+                Connection::with_timeouts(ours, Duration::from_secs(120), Duration::from_millis(20)),
+                theirs,
+            )
+        };
+
+        let err = conn.read_frame().await.unwrap_err();
+        assert!(err.downcast_ref::<ReadTimeout>().is_some());
+    }
+
+    #[tokio::test]
+    async fn streams_a_piece_payload_in_bounded_chunks() {
+        let data = vec![7u8; 40 * 1024];
+        let (ours, mut peer) = tokio::io::duplex(data.len() + 1024);
+        let mut conn = Connection::new(ours);
+
+        peer.write_all(&(9 + data.len() as u32).to_be_bytes()).await.unwrap();
+        peer.write_all(&[7]).await.unwrap();
+        peer.write_all(&3u32.to_be_bytes()).await.unwrap(); // index
+        peer.write_all(&0u32.to_be_bytes()).await.unwrap(); // begin
+        peer.write_all(&data).await.unwrap();
+
+        let Some(StreamFrame::Header(FrameHeader::Piece { index, begin, mut len })) =
+            conn.read_frame_header().await.unwrap()
+        else {
+            panic!("expected a piece header");
+        };
+        assert_eq!((index, begin, len), (3, 0, data.len() as u32));
+
+        let mut received = Vec::new();
+        while len > 0 {
+            let chunk = conn.read_payload_chunk(&mut len, 16 * 1024).await.unwrap();
+            assert!(chunk.len() <= 16 * 1024);
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, data);
+    }
+
+    #[tokio::test]
+    async fn streaming_a_payload_larger_than_frame_max_does_not_error() {
+        let data = vec![1u8; FRAME_MAX + 4096];
+        let (ours, mut peer) = tokio::io::duplex(data.len() + 1024);
+        let mut conn = Connection::new(ours);
+
+        peer.write_all(&(2 + data.len() as u32).to_be_bytes()).await.unwrap();
+        peer.write_all(&[20]).await.unwrap();
+        peer.write_all(&[9]).await.unwrap(); // ext_id
+        peer.write_all(&data).await.unwrap();
+
+        let Some(StreamFrame::Header(FrameHeader::Extended { ext_id, mut len })) =
+            conn.read_frame_header().await.unwrap()
+        else {
+            panic!("expected an extended header");
+        };
+        assert_eq!(ext_id, 9);
+        assert_eq!(len, data.len() as u32);
+
+        let mut total = 0usize;
+        while len > 0 {
+            let chunk = conn.read_payload_chunk(&mut len, 8192).await.unwrap();
+            total += chunk.len();
+        }
+        assert_eq!(total, data.len());
+    }
+
+    #[tokio::test]
+    async fn read_frame_header_still_buffers_small_frames_in_full() {
+        let (mut conn, mut peer) = test_connection();
+
+        peer.write_all(&5u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&[4]).await.unwrap();
+        peer.write_all(&9u32.to_be_bytes()).await.unwrap();
+
+        assert_eq!(
+            conn.read_frame_header().await.unwrap(),
+            Some(StreamFrame::Whole(Frame::Have(9)))
+        );
+    }
+
+    #[tokio::test]
+    async fn cancels_and_requeues_a_block_whose_reply_size_does_not_match() {
+        let (ours, mut peer) = tokio::io::duplex(BLOCK_SIZE as usize * 4 + 4096);
+        let mut conn = Connection::new(ours);
+
+        let index = 2u32;
+        let piece_len = BLOCK_SIZE;
+
+        let task = tokio::spawn(async move { conn.download_piece_windowed(index, piece_len, 1).await });
+
+        // First `Request`, for the whole (single-block) piece.
+        assert_eq!(
+            read_frame_from(&mut peer).await,
+            Frame::Request { index, begin: 0, length: piece_len }
+        );
+
+        // Reply with a `Piece` payload half the requested size.
+        let short = vec![1u8; BLOCK_SIZE as usize / 2];
+        peer.write_all(&(9 + short.len() as u32).to_be_bytes()).await.unwrap();
+        peer.write_all(&[7]).await.unwrap();
+        peer.write_all(&index.to_be_bytes()).await.unwrap();
+        peer.write_all(&0u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&short).await.unwrap();
+
+        // The mismatched block must be cancelled before it's re-requested.
+        assert_eq!(
+            read_frame_from(&mut peer).await,
+            Frame::Cancel { index, begin: 0, length: piece_len }
+        );
+        assert_eq!(
+            read_frame_from(&mut peer).await,
+            Frame::Request { index, begin: 0, length: piece_len }
+        );
+
+        // Now satisfy the re-request so the task can finish.
+        let data = vec![1u8; piece_len as usize];
+        peer.write_all(&(9 + data.len() as u32).to_be_bytes()).await.unwrap();
+        peer.write_all(&[7]).await.unwrap();
+        peer.write_all(&index.to_be_bytes()).await.unwrap();
+        peer.write_all(&0u32.to_be_bytes()).await.unwrap();
+        peer.write_all(&data).await.unwrap();
+
+        let piece = task.await.unwrap().unwrap();
+        assert_eq!(piece.as_ref(), data.as_slice());
+    }
+
+    /// Reads one complete frame off `peer`'s side of the duplex, the way a
+    /// real peer would see whatever `Connection::write_frame` just sent.
+    async fn read_frame_from(peer: &mut tokio::io::DuplexStream) -> Frame {
+        let mut len_buf = [0u8; 4];
+        peer.read_exact(&mut len_buf).await.unwrap();
+        let len = u32::from_be_bytes(len_buf) as usize;
+        let mut rest = BytesMut::zeroed(len);
+        peer.read_exact(&mut rest).await.unwrap();
+        let mut framed = BytesMut::new();
+        framed.put_u32(len as u32);
+        framed.extend_from_slice(&rest);
+        decode_frame(&mut framed).unwrap().unwrap()
     }
 }
@@ -0,0 +1,242 @@
+//! BEP-9 `ut_metadata` exchange, layered on top of the BEP-10 extended
+//! messages handled by [`Connection`](crate::peer::connection::Connection).
+
+use std::collections::HashMap;
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::peer::codec::FrameIo;
+use crate::peer::connection::Frame;
+
+/// Extension name looked up in (and advertised in) the `"m"` dictionary.
+const UT_METADATA: &str = "ut_metadata";
+
+/// Id we advertise `ut_metadata` under in our own handshake. Its value only
+/// needs to be consistent with what we put in that handshake; peers tag the
+/// `ut_metadata` messages they send us with it, we don't have to match theirs.
+const LOCAL_UT_METADATA_ID: u8 = 1;
+
+/// Every metadata piece but the last is exactly this many bytes, per BEP-9.
+const METADATA_PIECE_SIZE: usize = 16 * 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ExtensionHandshake {
+    m: HashMap<String, u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata_size: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct MetadataRequest {
+    msg_type: u8,
+    piece: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct MetadataPieceHeader {
+    msg_type: u8,
+    piece: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    total_size: Option<usize>,
+}
+
+const MSG_REQUEST: u8 = 0;
+const MSG_DATA: u8 = 1;
+const MSG_REJECT: u8 = 2;
+
+/// Sends our extension handshake and waits for the peer's, returning the
+/// peer's `ut_metadata` id and the advertised `metadata_size`. Generic over
+/// [`FrameIo`] so callers can drive it over a plain [`Connection`](crate::peer::connection::Connection)
+/// or a `Framed` codec, whichever they already have on hand.
+pub async fn exchange_handshake<C: FrameIo>(conn: &mut C) -> crate::Result<(u8, usize)> {
+    let handshake = ExtensionHandshake {
+        m: HashMap::from([(UT_METADATA.to_string(), LOCAL_UT_METADATA_ID)]),
+        metadata_size: None,
+    };
+    let payload = serde_bencode::to_bytes(&handshake).context("encode extension handshake")?;
+
+    conn.write_frame(&Frame::Extended {
+        ext_id: 0,
+        payload: Bytes::from(payload),
+    })
+    .await?;
+
+    loop {
+        match conn.read_frame().await?.context("peer closed connection")? {
+            Frame::Extended { ext_id: 0, payload } => {
+                let handshake: ExtensionHandshake =
+                    serde_bencode::from_bytes(&payload).context("decode extension handshake")?;
+                let ut_metadata_id = *handshake
+                    .m
+                    .get(UT_METADATA)
+                    .context("peer does not support ut_metadata")?;
+                let metadata_size = handshake
+                    .metadata_size
+                    .context("peer did not advertise metadata_size")?;
+                return Ok((ut_metadata_id, metadata_size));
+            }
+            // Anything else (bitfield, have, ...) arriving before the
+            // extension handshake is harmless, keep waiting.
+            _ => continue,
+        }
+    }
+}
+
+/// Downloads the whole `info` dict in 16 KiB pieces, verifies it against
+/// `info_hash`, and returns the raw bencoded bytes ready to be bdecoded into
+/// `Torrent.info`. Generic over [`FrameIo`], same as [`exchange_handshake`].
+/// When `conn` is a `Framed` rather than a [`Connection`](crate::peer::connection::Connection)
+/// directly, each piece reply is buffered whole rather than streamed (see
+/// [`FrameIo`](crate::peer::codec)'s module docs) — fine here since BEP-9
+/// caps a piece at 16 KiB, well under `Connection`'s 64 KiB frame ceiling.
+pub async fn fetch_info_dict<C: FrameIo>(
+    conn: &mut C,
+    ut_metadata_id: u8,
+    metadata_size: usize,
+    info_hash: [u8; 20],
+) -> crate::Result<Bytes> {
+    let num_pieces = metadata_size.div_ceil(METADATA_PIECE_SIZE);
+    let mut dict = vec![0u8; metadata_size];
+
+    for piece in 0..num_pieces as u32 {
+        let request = MetadataRequest {
+            msg_type: MSG_REQUEST,
+            piece,
+        };
+        let payload = serde_bencode::to_bytes(&request).context("encode metadata request")?;
+
+        conn.write_frame(&Frame::Extended {
+            ext_id: ut_metadata_id,
+            payload: Bytes::from(payload),
+        })
+        .await?;
+
+        let chunk = loop {
+            match conn.read_frame().await?.context("peer closed connection")? {
+                Frame::Extended { ext_id, payload } if ext_id == LOCAL_UT_METADATA_ID => break payload,
+                // Some other extension (or a handshake retransmit); keep
+                // waiting for the piece reply we actually asked for.
+                _ => continue,
+            }
+        };
+
+        let (header, rest) = split_bencoded_dict(&chunk)?;
+        let header: MetadataPieceHeader =
+            serde_bencode::from_bytes(header).context("decode metadata piece header")?;
+
+        match header.msg_type {
+            MSG_DATA => {}
+            MSG_REJECT => bail!("peer rejected metadata piece {piece}"),
+            n => bail!("unexpected ut_metadata msg_type {n}"),
+        }
+        if header.piece != piece {
+            bail!("peer sent metadata piece {} while {piece} was requested", header.piece);
+        }
+
+        let offset = piece as usize * METADATA_PIECE_SIZE;
+        let end = (offset + rest.len()).min(metadata_size);
+        dict[offset..end].copy_from_slice(&rest[..end - offset]);
+    }
+
+    let dict = Bytes::from(dict);
+
+    let mut hasher = Sha1::new();
+    hasher.update(&dict);
+    let digest: [u8; 20] = hasher.finalize().into();
+    if digest != info_hash {
+        bail!("metadata info-hash mismatch");
+    }
+
+    Ok(dict)
+}
+
+/// `msg_type`/`piece`/`total_size` dicts are followed, in the same extended
+/// frame, by the raw metadata bytes for that piece. We don't track how many
+/// bytes `serde_bencode` consumed, so walk the bencoding ourselves to find
+/// where the dict ends and the payload begins.
+fn split_bencoded_dict(buf: &[u8]) -> crate::Result<(&[u8], &[u8])> {
+    let len = bencoded_value_len(buf)?;
+    Ok(buf.split_at(len))
+}
+
+/// Returns the number of bytes the leading bencoded value in `buf` occupies.
+/// Bounds-checked throughout since `buf` is untrusted peer data: a
+/// declared length that would run past the end of `buf` is an error
+/// instead of a slice that panics.
+fn bencoded_value_len(buf: &[u8]) -> crate::Result<usize> {
+    match buf.first() {
+        Some(b'i') => {
+            let end = buf.iter().position(|&b| b == b'e').context("unterminated integer")?;
+            Ok(end + 1)
+        }
+        Some(b'l') => {
+            let mut pos = 1;
+            loop {
+                match buf.get(pos) {
+                    Some(b'e') => break,
+                    Some(_) => pos += bencoded_value_len(&buf[pos..])?,
+                    None => bail!("unterminated list"),
+                }
+            }
+            Ok(pos + 1)
+        }
+        Some(b'd') => {
+            let mut pos = 1;
+            loop {
+                match buf.get(pos) {
+                    Some(b'e') => break,
+                    Some(_) => {
+                        pos += bencoded_value_len(&buf[pos..])?; // key
+                        pos += bencoded_value_len(&buf[pos..])?; // value
+                    }
+                    None => bail!("unterminated dict"),
+                }
+            }
+            Ok(pos + 1)
+        }
+        Some(b'0'..=b'9') => {
+            let colon = buf.iter().position(|&b| b == b':').context("malformed byte string")?;
+            let len: usize = std::str::from_utf8(&buf[..colon])?.parse()?;
+            let total = colon + 1 + len;
+            if total > buf.len() {
+                bail!("truncated bencoded byte string");
+            }
+            Ok(total)
+        }
+        _ => bail!("malformed bencoded value"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_a_dict_header_from_its_trailing_payload() {
+        let buf = b"d8:msg_typei1e5:piecei0ee\xAA\xBB\xCC";
+        let (header, rest) = split_bencoded_dict(buf).unwrap();
+        assert_eq!(header, b"d8:msg_typei1e5:piecei0ee");
+        assert_eq!(rest, b"\xAA\xBB\xCC");
+    }
+
+    #[test]
+    fn errors_instead_of_panicking_on_a_byte_string_longer_than_the_buffer() {
+        // Regression test: this exact input used to panic with a slice
+        // out-of-bounds instead of returning an error.
+        let err = bencoded_value_len(b"d3:key50:short").unwrap_err();
+        assert!(err.to_string().contains("truncated"));
+    }
+
+    #[test]
+    fn errors_on_an_unterminated_list() {
+        assert!(bencoded_value_len(b"li1ei2e").is_err());
+    }
+
+    #[test]
+    fn errors_on_an_unterminated_dict() {
+        assert!(bencoded_value_len(b"d3:keyi1e").is_err());
+    }
+}